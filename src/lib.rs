@@ -4,17 +4,27 @@ extern crate serialport;
 extern crate packed_struct;
 #[macro_use]
 extern crate packed_struct_codegen;
+extern crate futures;
+extern crate async_trait;
 
 use multiwii_serial_protocol::{MspCommandCode, MspPacket, MspPacketDirection, MspParser};
 use serialport::SerialPort;
 use packed_struct::prelude::*;
+use futures::channel::oneshot;
+use futures::{select_biased, FutureExt};
 
-use async_std::sync::{channel, Arc, Mutex, Sender, Receiver};
+use async_std::sync::{channel, Arc, Mutex, Sender, Receiver, TryRecvError};
 use async_std::{io, task};
 use async_std::future;
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::{TcpStream, ToSocketAddrs, UdpSocket};
 
+use std::collections::{HashMap, VecDeque};
 use std::time::Duration;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 
 // TODO: move this to multiwii_serial_protocol.rs library
@@ -26,11 +36,6 @@ pub struct MspDataFlashRead {
     pub read_length: u16,
 }
 
-pub struct MspDataFlashReply {
-    pub read_address: u32,
-    pub payload: Vec<u8>,
-}
-
 #[derive(PackedStruct, Debug, Copy, Clone)]
 #[packed_struct(bytes = "1", endian = "lsb", bit_numbering = "msb0")]
 pub struct MspDataFlashSummaryReply {
@@ -79,19 +84,172 @@ pub struct ModeRange {
     pub end_step: u8,
 }
 
+// Zero-sized (de)serializable marker: used as the request payload for
+// parameterless GET commands, and as the response type for commands whose
+// only reply is an empty ack (e.g. MSP_SET_*).
+#[derive(Debug, Copy, Clone)]
+pub struct MspEmpty;
+
+impl PackedStructSlice for MspEmpty {
+    fn pack_to_slice(&self, _output: &mut [u8]) -> Result<(), PackingError> {
+        Ok(())
+    }
+
+    fn unpack_from_slice(_src: &[u8]) -> Result<Self, PackingError> {
+        Ok(MspEmpty)
+    }
+
+    fn packed_bytes_size(_opt_self: Option<&Self>) -> Result<usize, PackingError> {
+        Ok(0)
+    }
+}
+
+#[derive(PackedStruct, Debug, Copy, Clone)]
+#[packed_struct(bytes = "16", endian = "lsb", bit_numbering = "msb0")]
+pub struct MspRawGps {
+    pub fix: u8,
+    pub num_sat: u8,
+    pub lat: i32,
+    pub lon: i32,
+    pub altitude: u16,
+    pub speed: u16,
+    pub ground_course: u16,
+}
+
+#[derive(PackedStruct, Debug, Copy, Clone)]
+#[packed_struct(bytes = "6", endian = "lsb", bit_numbering = "msb0")]
+pub struct MspAttitude {
+    pub roll: i16,
+    pub pitch: i16,
+    pub yaw: i16,
+}
+
+#[derive(PackedStruct, Debug, Copy, Clone)]
+#[packed_struct(bytes = "16", endian = "lsb", bit_numbering = "msb0")]
+pub struct MspRc {
+    #[packed_field(element_size_bytes="2")]
+    pub channels: [u16; 8], // roll, pitch, yaw, throttle, aux1-4
+}
+
+#[derive(PackedStruct, Debug, Copy, Clone)]
+#[packed_struct(bytes = "3", endian = "lsb", bit_numbering = "msb0")]
+pub struct MspPidValues {
+    pub p: u8,
+    pub i: u8,
+    pub d: u8,
+}
+
+#[derive(PackedStruct, Debug, Copy, Clone)]
+#[packed_struct(bytes = "30", endian = "lsb", bit_numbering = "msb0")]
+pub struct MspPid {
+    #[packed_field(element_size_bytes="3")]
+    pub items: [MspPidValues; 10], // ROLL, PITCH, YAW, ALT, POS, POSR, NAVR, LEVEL, MAG, VEL
+}
+
 // TODO: extract this code to rust module(different file)
 
+// Map from msp command code to the queue of callers currently waiting on a
+// reply for that command. MSP v2 carries no on-wire request id, so replies
+// are matched to waiters FIFO, per command.
+// Each waiter carries a caller-local id so a timed-out caller can pick its
+// own (by then dead) entry back out of the deque instead of leaving it for
+// process_route to eventually find and discard.
+type ReplyWaiters = Arc<Mutex<HashMap<u16, VecDeque<(u64, oneshot::Sender<MspPacket>)>>>>;
+
+// Source of the ids above; only needs to be unique, never reused or reset.
+static NEXT_WAITER_ID: AtomicU64 = AtomicU64::new(0);
+
+// Result of fetching one dataflash chunk: the payload plus the updated
+// address bookkeeping, so both read_chunk and the Read impl can drive it.
+struct FlashChunk {
+    payload: Vec<u8>,
+    next_address: u32,
+    received_address: u32,
+}
+
+// Fetches the next dataflash chunk starting at `next_address`. Takes owned
+// handles rather than `&FlashDataFile` so it can be boxed into a `'static`
+// future and polled from `poll_read` without borrowing `self`.
+async fn fetch_flash_chunk(
+    waiters: ReplyWaiters,
+    msp_writer_bulk_send: Sender<MspPacket>,
+    parser_locked: Arc<Mutex<MspParser>>,
+    used_size: u32,
+    mut next_address: u32,
+    mut received_address: u32,
+) -> io::Result<FlashChunk> {
+    loop {
+        let payload = MspDataFlashRead {
+            read_address: next_address,
+            read_length: 0x1000,
+        };
+        let packed = payload.pack();
+
+        let packet = multiwii_serial_protocol::MspPacket {
+            cmd: multiwii_serial_protocol::MspCommandCode::MSP_DATAFLASH_READ as u16,
+            direction: multiwii_serial_protocol::MspPacketDirection::ToFlightController,
+            data: packed.to_vec(),
+        };
+
+        let cmd = packet.cmd;
+        let (waiter_id, waiter) = INavMsp::wait_for(&waiters, cmd).await;
+        msp_writer_bulk_send.send(packet).await;
+
+        let timeout_res = future::timeout(Duration::from_millis(50), waiter).await;
+
+        // resend the packet
+        match timeout_res {
+            Err(_) => {
+                INavMsp::forget_waiter(&waiters, cmd, waiter_id).await;
+                (*parser_locked.lock().await).reset();
+            }
+            Ok(Err(_)) => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "device disconnected")),
+            Ok(Ok(packet)) => {
+                let mut s = [0; 4];
+                s.copy_from_slice(&packet.data[..4]);
+                let read_address = u32::from_le_bytes(s);
+                let read_payload = packet.data[4..].to_vec();
+
+                if read_address >= next_address {
+                    received_address = read_address;
+                    next_address = read_address + read_payload.len() as u32;
+                } else {
+                    continue;
+                }
+
+                println!("{:?}/{:?}", read_address, used_size);
+
+                if received_address >= used_size {
+                    return Ok(FlashChunk { payload: vec![], next_address, received_address });
+                }
+
+                return Ok(FlashChunk { payload: read_payload, next_address, received_address });
+            }
+        }
+    }
+}
+
 pub struct FlashDataFile {
-    chunk_recv: Receiver<MspDataFlashReply>,
-    msp_writer_send: Sender<MspPacket>,
+    waiters: ReplyWaiters,
+    msp_writer_bulk_send: Sender<MspPacket>,
     parser_locked: Arc<Mutex<MspParser>>,
     used_size: u32,
     next_address: u32,
     // requested_address: u32,
     received_address: u32,
+    // bytes of the last fetched chunk not yet handed out through `Read::poll_read`
+    leftover: Vec<u8>,
+    inflight: Option<Pin<Box<dyn Future<Output = io::Result<FlashChunk>> + Send>>>,
+    // flips INavMsp's flash-read-in-progress flag back off once this stream is dropped
+    open_flag: Arc<AtomicBool>,
+}
+
+impl Drop for FlashDataFile {
+    fn drop(&mut self) {
+        self.open_flag.store(false, Ordering::Relaxed);
+    }
 }
 
-// TODO: we should return interface that implements async_std::io::Read trait
 // TODO: why not return move the payload vec instead of the io result??
 impl FlashDataFile {
     pub async fn read_chunk(&mut self) -> io::Result<Vec<u8>> {
@@ -99,52 +257,190 @@ impl FlashDataFile {
             return Err(io::Error::new(io::ErrorKind::ConnectionReset, "use after close"));
         }
 
+        let chunk = fetch_flash_chunk(
+            self.waiters.clone(),
+            self.msp_writer_bulk_send.clone(),
+            self.parser_locked.clone(),
+            self.used_size,
+            self.next_address,
+            self.received_address,
+        ).await?;
+
+        self.next_address = chunk.next_address;
+        self.received_address = chunk.received_address;
+
+        Ok(chunk.payload)
+    }
+
+    // Resumes a partially-downloaded log at `address`, discarding any buffered
+    // leftover bytes and in-flight request from the previous position.
+    pub fn seek_to(&mut self, address: u32) {
+        self.next_address = address;
+        self.received_address = 0;
+        self.leftover.clear();
+        self.inflight = None;
+    }
+}
+
+impl io::Read for FlashDataFile {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
         loop {
-            if self.next_address > self.received_address || self.next_address == 0 {
-                let payload = MspDataFlashRead {
-                    read_address: self.next_address,
-                    read_length: 0x1000,
-                };
-                let packed = payload.pack();
+            if !this.leftover.is_empty() {
+                let n = std::cmp::min(buf.len(), this.leftover.len());
+                buf[..n].copy_from_slice(&this.leftover[..n]);
+                this.leftover.drain(..n);
+                return Poll::Ready(Ok(n));
+            }
 
-                let packet = multiwii_serial_protocol::MspPacket {
-                    cmd: multiwii_serial_protocol::MspCommandCode::MSP_DATAFLASH_READ as u16,
-                    direction: multiwii_serial_protocol::MspPacketDirection::ToFlightController,
-                    data: packed.to_vec(),
-                };
+            if this.received_address >= this.used_size {
+                return Poll::Ready(Ok(0));
+            }
 
-                self.msp_writer_send.send(packet).await;
+            if this.inflight.is_none() {
+                this.inflight = Some(Box::pin(fetch_flash_chunk(
+                    this.waiters.clone(),
+                    this.msp_writer_bulk_send.clone(),
+                    this.parser_locked.clone(),
+                    this.used_size,
+                    this.next_address,
+                    this.received_address,
+                )));
             }
 
-            let timeout_res = future::timeout(Duration::from_millis(50), self.chunk_recv.recv()).await;
+            let fut = this.inflight.as_mut().unwrap();
+            match fut.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => {
+                    this.inflight = None;
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Ready(Ok(chunk)) => {
+                    this.inflight = None;
+                    this.next_address = chunk.next_address;
+                    this.received_address = chunk.received_address;
+                    this.leftover = chunk.payload;
+                    // loop back around: either serve from the new leftover or hit EOF
+                }
+            }
+        }
+    }
+}
 
-            // resend the packet
-            if timeout_res.is_ok() {
-                match timeout_res.unwrap() {
-                    None => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "device disconnected")),
-                    Some(packet) => {
+// TODO: extract this to rust module(different file)
 
-                        if packet.read_address >= self.next_address {
-                            self.received_address = packet.read_address;
-                            self.next_address = packet.read_address + packet.payload.len() as u32;
-                        } else {
-                            continue;
-                        }
+// Lets INavMsp::start run over any byte stream (serial, TCP, UDP, ...)
+// instead of being hard-wired to a serialport::SerialPort.
+#[async_trait::async_trait]
+pub trait MspTransport: Send {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    async fn write(&mut self, buf: &[u8]) -> io::Result<()>;
+    fn try_clone(&self) -> io::Result<Self> where Self: Sized;
+}
 
-                        println!("{:?}/{:?}", packet.read_address, self.used_size);
+pub struct SerialMspTransport {
+    serial: Box<dyn SerialPort>,
+}
 
-                        if self.received_address >= self.used_size {
-                            return Ok(vec![]);
-                        }
+impl SerialMspTransport {
+    pub fn new(serial: Box<dyn SerialPort>) -> SerialMspTransport {
+        SerialMspTransport { serial }
+    }
+}
 
-                        return Ok(packet.payload);
-                    }
+#[async_trait::async_trait]
+impl MspTransport for SerialMspTransport {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::io::Read;
+        self.serial.read(buf)
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+        // because inav doesn't support uart flow control, we simply try write untill success
+        loop {
+            match self.serial.write(buf) {
+                Ok(_) => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                    // controller is busy/serial buffer is full, sleep and attempt write again
+                    task::sleep(Duration::from_millis(1)).await;
                 }
-            } else {
-                (*self.parser_locked.lock().await).reset();
+                Err(e) => return Err(e),
             }
         }
     }
+
+    fn try_clone(&self) -> io::Result<SerialMspTransport> {
+        Ok(SerialMspTransport { serial: self.serial.try_clone()? })
+    }
+}
+
+pub struct TcpMspTransport {
+    stream: TcpStream,
+}
+
+impl TcpMspTransport {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpMspTransport> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(TcpMspTransport { stream })
+    }
+}
+
+#[async_trait::async_trait]
+impl MspTransport for TcpMspTransport {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf).await {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed by flight controller")),
+            Ok(n) => Ok(n),
+            Err(e) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, e)),
+        }
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        // the stream handles its own flow control, unlike the serial link
+        self.stream.write_all(buf).await.map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e))
+    }
+
+    fn try_clone(&self) -> io::Result<TcpMspTransport> {
+        Ok(TcpMspTransport { stream: self.stream.clone() })
+    }
+}
+
+pub struct UdpMspTransport {
+    socket: Arc<UdpSocket>,
+}
+
+impl UdpMspTransport {
+    pub async fn connect<A: ToSocketAddrs>(bind_addr: A, remote_addr: A) -> io::Result<UdpMspTransport> {
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(remote_addr).await?;
+        Ok(UdpMspTransport { socket: Arc::new(socket) })
+    }
+}
+
+#[async_trait::async_trait]
+impl MspTransport for UdpMspTransport {
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.recv(buf).await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.socket.send(buf).await.map(|_| ())
+    }
+
+    fn try_clone(&self) -> io::Result<UdpMspTransport> {
+        Ok(UdpMspTransport { socket: self.socket.clone() })
+    }
+}
+
+// Write priority classes, highest first. A large dataflash download is
+// always Bulk, so it never starves a time-sensitive command like arm/disarm.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MspPriority {
+    High,
+    Normal,
+    Bulk,
 }
 
 pub struct INavMsp {
@@ -152,29 +448,37 @@ pub struct INavMsp {
 
     msp_reader_send: Sender<MspPacket>,
     msp_reader_recv: Receiver<MspPacket>,
-    msp_writer_send: Sender<MspPacket>,
-    msp_writer_recv: Receiver<MspPacket>,
 
-    mode_ranges_recv: Receiver<MspModeRangesReplay>,
-    mode_ranges_send: Sender<MspModeRangesReplay>,
-    set_mode_range_ack_recv: Receiver<()>,
-    set_mode_range_ack_send: Sender<()>,
-    summary_recv: Receiver<MspDataFlashSummaryReply>,
-    summary_send: Sender<MspDataFlashSummaryReply>,
-    chunk_recv: Receiver<MspDataFlashReply>,
-    chunk_send: Sender<MspDataFlashReply>,
+    msp_writer_high_send: Sender<MspPacket>,
+    msp_writer_high_recv: Receiver<MspPacket>,
+    msp_writer_normal_send: Sender<MspPacket>,
+    msp_writer_normal_recv: Receiver<MspPacket>,
+    msp_writer_bulk_send: Sender<MspPacket>,
+    msp_writer_bulk_recv: Receiver<MspPacket>,
+
+    waiters: ReplyWaiters,
+
+    should_stop: Arc<AtomicBool>,
+    handles: Vec<task::JoinHandle<()>>,
+    flash_data_open: Arc<AtomicBool>,
+}
+
+impl Drop for INavMsp {
+    fn drop(&mut self) {
+        // best effort: tell the read loop in process_input to exit once its
+        // current read returns. close() is still the way to wait for the
+        // background tasks to actually stop.
+        self.should_stop.store(true, Ordering::Relaxed);
+    }
 }
 
 impl INavMsp {
     // Create a new parserSerialPort
     pub fn new() -> INavMsp {
         let (msp_reader_send, msp_reader_recv) = channel::<MspPacket>(1);
-        let (msp_writer_send, msp_writer_recv) = channel::<MspPacket>(1);
-
-        let (mode_ranges_send, mode_ranges_recv) = channel::<MspModeRangesReplay>(1);
-        let (set_mode_range_ack_send, set_mode_range_ack_recv) = channel::<()>(1);
-        let (summary_send, summary_recv) = channel::<MspDataFlashSummaryReply>(1);
-        let (chunk_send, chunk_recv) = channel::<MspDataFlashReply>(1);
+        let (msp_writer_high_send, msp_writer_high_recv) = channel::<MspPacket>(1);
+        let (msp_writer_normal_send, msp_writer_normal_recv) = channel::<MspPacket>(1);
+        let (msp_writer_bulk_send, msp_writer_bulk_recv) = channel::<MspPacket>(1);
 
         let parser = MspParser::new();
         let parser_locked = Arc::new(Mutex::new(parser));
@@ -183,41 +487,164 @@ impl INavMsp {
             parser_locked: parser_locked,
             msp_reader_send: msp_reader_send,
             msp_reader_recv: msp_reader_recv,
-            msp_writer_send: msp_writer_send,
-            msp_writer_recv: msp_writer_recv,
-
-            mode_ranges_send: mode_ranges_send,
-            mode_ranges_recv: mode_ranges_recv,
-            set_mode_range_ack_recv: set_mode_range_ack_recv,
-            set_mode_range_ack_send: set_mode_range_ack_send,
-            summary_send: summary_send,
-            summary_recv: summary_recv,
-            chunk_send: chunk_send,
-            chunk_recv: chunk_recv,
+            msp_writer_high_send: msp_writer_high_send,
+            msp_writer_high_recv: msp_writer_high_recv,
+            msp_writer_normal_send: msp_writer_normal_send,
+            msp_writer_normal_recv: msp_writer_normal_recv,
+            msp_writer_bulk_send: msp_writer_bulk_send,
+            msp_writer_bulk_recv: msp_writer_bulk_recv,
+
+            waiters: Arc::new(Mutex::new(HashMap::new())),
+
+            should_stop: Arc::new(AtomicBool::new(false)),
+            handles: vec![],
+            flash_data_open: Arc::new(AtomicBool::new(false)),
         };
 	  }
 
-    pub fn start(&self, serial: Box<dyn SerialPort>) {
-        let serial_clone = serial.try_clone().unwrap();
+    // Signals the background tasks to stop and waits for them to actually
+    // exit. Closing the writer queues is what lets process_output return;
+    // should_stop is what lets the blocking serial read loop in
+    // process_input return.
+    pub async fn close(mut self) {
+        self.should_stop.store(true, Ordering::Relaxed);
+
+        let handles = std::mem::take(&mut self.handles);
+
+        // drop the reader/writer senders now so the background tasks see
+        // their channels disconnected and can return, instead of waiting
+        // for `self` to go out of scope after the handles are awaited below
+        drop(self);
+
+        for handle in handles {
+            handle.await;
+        }
+    }
+
+    async fn send_with_priority(&self, packet: MspPacket, priority: MspPriority) {
+        match priority {
+            MspPriority::High => self.msp_writer_high_send.send(packet).await,
+            MspPriority::Normal => self.msp_writer_normal_send.send(packet).await,
+            MspPriority::Bulk => self.msp_writer_bulk_send.send(packet).await,
+        }
+    }
+
+    // Pulls the next outgoing packet from the highest-priority non-empty class.
+    async fn next_outgoing(
+        high_recv: &Receiver<MspPacket>,
+        normal_recv: &Receiver<MspPacket>,
+        bulk_recv: &Receiver<MspPacket>,
+    ) -> Option<MspPacket> {
+        // Drain whatever is already queued first, highest priority class
+        // first, so a closed channel can never preempt real data sitting in
+        // a lower one (recv() on a disconnected channel resolves instantly,
+        // which would otherwise outrun select_biased!'s ordering below).
+        let mut high_disconnected = false;
+        let mut normal_disconnected = false;
+        let mut bulk_disconnected = false;
+
+        match high_recv.try_recv() {
+            Ok(packet) => return Some(packet),
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => high_disconnected = true,
+        }
+        match normal_recv.try_recv() {
+            Ok(packet) => return Some(packet),
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => normal_disconnected = true,
+        }
+        match bulk_recv.try_recv() {
+            Ok(packet) => return Some(packet),
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => bulk_disconnected = true,
+        }
+
+        if high_disconnected && normal_disconnected && bulk_disconnected {
+            return None;
+        }
+
+        // Nothing queued right now; park until one of the still-open queues
+        // wakes us instead of busy-polling. Only open queues are raced here:
+        // a disconnected one (e.g. High/Normal after close() drops self
+        // while a FlashDataFile still holds its own Bulk sender clone) is
+        // left out so it can't be mistaken for "everything stopped".
+        match (high_disconnected, normal_disconnected, bulk_disconnected) {
+            (false, false, false) => select_biased! {
+                packet = high_recv.recv().fuse() => packet,
+                packet = normal_recv.recv().fuse() => packet,
+                packet = bulk_recv.recv().fuse() => packet,
+            },
+            (false, false, true) => select_biased! {
+                packet = high_recv.recv().fuse() => packet,
+                packet = normal_recv.recv().fuse() => packet,
+            },
+            (false, true, false) => select_biased! {
+                packet = high_recv.recv().fuse() => packet,
+                packet = bulk_recv.recv().fuse() => packet,
+            },
+            (true, false, false) => select_biased! {
+                packet = normal_recv.recv().fuse() => packet,
+                packet = bulk_recv.recv().fuse() => packet,
+            },
+            (false, true, true) => high_recv.recv().await,
+            (true, false, true) => normal_recv.recv().await,
+            (true, true, false) => bulk_recv.recv().await,
+            (true, true, true) => None,
+        }
+    }
+
+    pub fn start<T: MspTransport + 'static>(&mut self, transport: T) {
+        let transport_clone = transport.try_clone().unwrap();
 
-        INavMsp::process_input(serial, self.parser_locked.clone(), self.msp_reader_send.clone());
-        INavMsp::process_output(serial_clone, self.msp_writer_recv.clone());
-        INavMsp::process_route(
+        let input_handle = INavMsp::process_input(
+            transport,
+            self.parser_locked.clone(),
+            self.msp_reader_send.clone(),
+            self.should_stop.clone(),
+        );
+        let output_handle = INavMsp::process_output(
+            transport_clone,
+            self.msp_writer_high_recv.clone(),
+            self.msp_writer_normal_recv.clone(),
+            self.msp_writer_bulk_recv.clone(),
+        );
+        let route_handle = INavMsp::process_route(
             self.msp_reader_recv.clone(),
-            self.mode_ranges_send.clone(),
-            self.set_mode_range_ack_send.clone(),
-            self.summary_send.clone(),
-            self.chunk_send.clone(),
+            self.waiters.clone(),
         );
+
+        self.handles.push(input_handle);
+        self.handles.push(output_handle);
+        self.handles.push(route_handle);
+    }
+
+    // Register a waiter for the next reply to `cmd` before sending the
+    // matching request, so the reply can't race ahead of the registration.
+    async fn wait_for(waiters: &ReplyWaiters, cmd: u16) -> (u64, oneshot::Receiver<MspPacket>) {
+        let (waiter_send, waiter_recv) = oneshot::channel::<MspPacket>();
+        let id = NEXT_WAITER_ID.fetch_add(1, Ordering::Relaxed);
+
+        (*waiters.lock().await)
+            .entry(cmd)
+            .or_insert_with(VecDeque::new)
+            .push_back((id, waiter_send));
+
+        return (id, waiter_recv);
+    }
+
+    // Drops a caller's own waiter after it gave up (e.g. on timeout), so a
+    // dead entry doesn't sit in the deque until some later, unrelated reply
+    // for the same cmd happens to pop it.
+    async fn forget_waiter(waiters: &ReplyWaiters, cmd: u16, id: u64) {
+        if let Some(deque) = (*waiters.lock().await).get_mut(&cmd) {
+            deque.retain(|(entry_id, _)| *entry_id != id);
+        }
     }
 
     fn process_route(
         msp_reader_recv: Receiver<MspPacket>,
-        mode_ranges_send: Sender<MspModeRangesReplay>,
-        set_mode_range_ack_send: Sender<()>,
-        summary_send: Sender<MspDataFlashSummaryReply>,
-        chunk_send: Sender<MspDataFlashReply>,
-    ) {
+        waiters: ReplyWaiters,
+    ) -> task::JoinHandle<()> {
         task::spawn(async move {
             loop {
                 let packet = match msp_reader_recv.recv().await {
@@ -229,60 +656,58 @@ impl INavMsp {
                     continue;
                 }
 
-                if packet.cmd == MspCommandCode::MSP_MODE_RANGES as u16 {
-                    let ranges = MspModeRangesReplay::unpack_from_slice(&packet.data).unwrap();
-                    mode_ranges_send.send(ranges).await;
-                }
-
-                if packet.cmd == MspCommandCode::MSP_SET_MODE_RANGE as u16 {
-                    // packet data should be empty, so just signal ack is received
-                    set_mode_range_ack_send.send(()).await;
-                }
-
-                if packet.cmd == MspCommandCode::MSP_DATAFLASH_SUMMARY as u16 {
-                    let summary = MspDataFlashSummaryReply::unpack_from_slice(&packet.data).unwrap();
-                    summary_send.send(summary).await;
-                }
-
-                if packet.cmd == MspCommandCode::MSP_DATAFLASH_READ as u16 {
-                    // extract the read address from the packet
-                    let mut s = [0; 4];
-                    s.copy_from_slice(&packet.data[..4]);
-                    let packet_address = u32::from_le_bytes(s);
-
-                    // remove the last address bytes and send to remaning payload to file stream(stdout)
-                    let packet_payload = &packet.data[4..];
+                // A waiter whose caller gave up before removing it (e.g. a dropped
+                // in-flight flash read, or a timeout that has not reached
+                // forget_waiter yet) can still sit at the front of the deque with
+                // its receiver dropped; send() on it fails
+                // and hands the packet back, so keep popping until one actually
+                // accepts it (or the deque runs dry) instead of discarding the
+                // reply on the first dead entry.
+                let mut packet = packet;
+                loop {
+                    let waiter = (*waiters.lock().await)
+                        .get_mut(&packet.cmd)
+                        .and_then(|deque| deque.pop_front());
 
-                    let chunk = MspDataFlashReply {
-                        read_address: packet_address,
-                        payload: packet_payload.to_vec(),
+                    let (_, waiter_send) = match waiter {
+                        Some(waiter) => waiter,
+                        None => break,
                     };
-                    chunk_send.send(chunk).await;
+
+                    match waiter_send.send(packet) {
+                        Ok(()) => break,
+                        Err(returned_packet) => packet = returned_packet,
+                    }
                 }
 
                 // TODO: create debug flag for additional print on demand
                 // println!("{:?}", packet);
             }
-        });
+        })
     }
 
-    // TODO: return joinhandler, so we can stop the tasks on drop
-    fn process_input(
-        mut serial: Box<dyn SerialPort>,
+    fn process_input<T: MspTransport + 'static>(
+        mut transport: T,
         parser_locked: Arc<Mutex<MspParser>>,
-        msp_reader_send: Sender<MspPacket>
-    ) -> Arc<AtomicBool> {
-        let should_stop = Arc::new(AtomicBool::new(false));
-        let should_stop_clone = should_stop.clone();
-
-        // task 1: read into input channel from serial(reading from serial is blocking)
+        msp_reader_send: Sender<MspPacket>,
+        should_stop: Arc<AtomicBool>,
+    ) -> task::JoinHandle<()> {
+        // task 1: read into input channel from the transport
         task::spawn(async move {
             while should_stop.load(Ordering::Relaxed) == false {
-                let mut serial_buf: Vec<u8> = vec![0; 1000];
-                match serial.read(serial_buf.as_mut_slice()) {
-                    Ok(bytes) => {
+                let mut read_buf: Vec<u8> = vec![0; 1000];
+
+                // TCP/UDP reads never time out on their own on an idle link, so
+                // without this race a parked read would never let us back around
+                // the loop to notice should_stop and close() would hang forever;
+                // the serial transport's own read timeout just rides along.
+                let read_res = future::timeout(Duration::from_millis(100), transport.read(read_buf.as_mut_slice())).await;
+
+                match read_res {
+                    Err(_) => continue,
+                    Ok(Ok(bytes)) => {
                         for n in 0..bytes {
-                            match (*parser_locked.lock().await).parse(serial_buf[n]) {
+                            match (*parser_locked.lock().await).parse(read_buf[n]) {
                                 Ok(Some(p)) => {
                                     msp_reader_send.send(p).await
                                 },
@@ -291,22 +716,22 @@ impl INavMsp {
                             }
                         }
                     }
-                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => task::yield_now().await,
-                    Err(e) => eprintln!("{:?}", e),
+                    Ok(Err(ref e)) if e.kind() == io::ErrorKind::TimedOut => task::yield_now().await,
+                    Ok(Err(e)) => eprintln!("{:?}", e),
                 }
             }
-        });
-        return should_stop_clone;
+        })
 	  }
 
-    // TODO: return joinhandler, so we can stop the tasks on drop
-    fn process_output(
-        mut serial: Box<dyn SerialPort>,
-        msp_writer_recv: Receiver<MspPacket>,
-    ) {
+    fn process_output<T: MspTransport + 'static>(
+        mut transport: T,
+        msp_writer_high_recv: Receiver<MspPacket>,
+        msp_writer_normal_recv: Receiver<MspPacket>,
+        msp_writer_bulk_recv: Receiver<MspPacket>,
+    ) -> task::JoinHandle<()> {
         task::spawn(async move {
             loop {
-                let packet = match msp_writer_recv.recv().await {
+                let packet = match INavMsp::next_outgoing(&msp_writer_high_recv, &msp_writer_normal_recv, &msp_writer_bulk_recv).await {
                     None => break,
                     Some(packet) => packet,
                 };
@@ -318,36 +743,41 @@ impl INavMsp {
                     .serialize_v2(&mut output)
                     .expect("Failed to serialize");
 
-                // because inav doesn't support uart flow control, we simply try write untill success
-                loop {
-                    match serial.write(&output) {
-                        Ok(_) => break,
-                        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
-                            // controller is busy/serial buffer is full, sleep and attempt write again
-                            task::sleep(Duration::from_millis(1)).await;
-                        }
-                        Err(e) => eprintln!("failed to write{:?}", e),
-                    }
+                if let Err(e) = transport.write(&output).await {
+                    eprintln!("failed to write{:?}", e);
                 }
             }
-        });
+        })
 	  }
 
-    // TODO: because this is a serial protocol, we cannot allow two reads of the file at the same time.
-    //       so throw error, if this function is called while another file is open already
-    pub async fn open_flash_data(&self) -> FlashDataFile {
+    // because this is a serial protocol, we cannot allow two reads of the
+    // file at the same time, so throw an error if this function is called
+    // while another file is open already
+    pub async fn open_flash_data(&self) -> io::Result<FlashDataFile> {
+        if self.flash_data_open.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a flash data read is already in progress"));
+        }
+
         // await for summary
-        let summary = self.flash_summary().await;
-        let used_size = summary.unwrap().used_size_bytes;
+        let used_size = match self.flash_summary().await {
+            Ok(summary) => summary.used_size_bytes,
+            Err(e) => {
+                self.flash_data_open.store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
 
-        return FlashDataFile {
-            chunk_recv: self.chunk_recv.clone(),
-            msp_writer_send: self.msp_writer_send.clone(),
+        return Ok(FlashDataFile {
+            waiters: self.waiters.clone(),
+            msp_writer_bulk_send: self.msp_writer_bulk_send.clone(),
             parser_locked: self.parser_locked.clone(),
             used_size: used_size,
             next_address: 0u32,
             received_address: 0u32,
-        };
+            leftover: vec![],
+            inflight: None,
+            open_flag: self.flash_data_open.clone(),
+        });
 	  }
 
     pub async fn flash_summary(&self) -> io::Result<MspDataFlashSummaryReply> {
@@ -357,14 +787,18 @@ impl INavMsp {
             data: vec![],
         };
 
-        self.msp_writer_send.send(packet).await;
+        let cmd = packet.cmd;
+        let (waiter_id, waiter) = INavMsp::wait_for(&self.waiters, cmd).await;
+        self.send_with_priority(packet, MspPriority::Normal).await;
 
-        let timeout_res = future::timeout(Duration::from_millis(30), self.summary_recv.recv()).await;
-        if timeout_res.is_ok() {
-            return Ok(timeout_res.unwrap().unwrap());
+        let timeout_res = future::timeout(Duration::from_millis(30), waiter).await;
+        match timeout_res {
+            Ok(Ok(reply)) => Ok(MspDataFlashSummaryReply::unpack_from_slice(&reply.data).unwrap()),
+            _ => {
+                INavMsp::forget_waiter(&self.waiters, cmd, waiter_id).await;
+                Err(io::Error::new(io::ErrorKind::TimedOut, "timedout waiting for summary response"))
+            }
         }
-
-        return Err(io::Error::new(io::ErrorKind::TimedOut, "timedout waiting for summary response"));
 	  }
 
     pub async fn set_mode_range(&self, mode: ModeRange) -> io::Result<()> {
@@ -385,15 +819,19 @@ impl INavMsp {
             data: payload.pack().to_vec(),
         };
 
-        self.msp_writer_send.send(packet).await;
-
-        // TODO: we are not sure this ack is for our request, because there is no id for the request
-        let timeout_res = future::timeout(Duration::from_millis(30), self.set_mode_range_ack_recv.recv()).await;
-        if timeout_res.is_ok() {
-            return Ok(timeout_res.unwrap().unwrap());
+        let cmd = packet.cmd;
+        let (waiter_id, waiter) = INavMsp::wait_for(&self.waiters, cmd).await;
+        self.send_with_priority(packet, MspPriority::Normal).await;
+
+        // packet data should be empty, so receiving it is just an ack signal
+        let timeout_res = future::timeout(Duration::from_millis(30), waiter).await;
+        match timeout_res {
+            Ok(Ok(_)) => Ok(()),
+            _ => {
+                INavMsp::forget_waiter(&self.waiters, cmd, waiter_id).await;
+                Err(io::Error::new(io::ErrorKind::TimedOut, "timedout waiting for set mode range response"))
+            }
         }
-
-        return Err(io::Error::new(io::ErrorKind::TimedOut, "timedout waiting for set mode range response"));
 	  }
 
     pub async fn get_mode_ranges(&self) -> io::Result<Vec<ModeRange>> {
@@ -403,18 +841,20 @@ impl INavMsp {
             data: vec![],
         };
 
-        self.msp_writer_send.send(packet).await;
-
-        // TODO: we are not sure this ack is for our request, because there is no id for the request
-        // TODO: what if we are reading packet that was sent long time ago
-        // TODO: also currently if no one is reading the channges, we may hang
+        let cmd = packet.cmd;
+        let (waiter_id, waiter) = INavMsp::wait_for(&self.waiters, cmd).await;
+        self.send_with_priority(packet, MspPriority::Normal).await;
 
-        let timeout_res = future::timeout(Duration::from_millis(30), self.mode_ranges_recv.recv()).await;
-        if !timeout_res.is_ok() {
-            return Err(io::Error::new(io::ErrorKind::TimedOut, "timedout waiting for set mode range response"));
-        }
+        let timeout_res = future::timeout(Duration::from_millis(30), waiter).await;
+        let reply = match timeout_res {
+            Ok(Ok(reply)) => reply,
+            _ => {
+                INavMsp::forget_waiter(&self.waiters, cmd, waiter_id).await;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "timedout waiting for mode ranges response"));
+            }
+        };
 
-        let ranges_replay = timeout_res.unwrap().unwrap();
+        let ranges_replay = MspModeRangesReplay::unpack_from_slice(&reply.data).unwrap();
         let mut valid_ranges = vec![];
 
         // TODO: not all 20 ranges will be active, return only the active ranges
@@ -435,4 +875,105 @@ impl INavMsp {
         return Ok(valid_ranges);
 	  }
 
+    // Generic request/response path: packs `payload`, sends it as `cmd`, and
+    // unpacks whatever comes back into `T`. Adding a new command is then just
+    // a matter of declaring its PackedStruct(s) and a thin wrapper below,
+    // rather than wiring a dedicated reply channel.
+    pub async fn request<T: PackedStructSlice>(&self, cmd: MspCommandCode, payload: impl PackedStructSlice) -> io::Result<T> {
+        let data = payload.pack_to_vec()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("failed to pack request: {:?}", e)))?;
+
+        let packet = MspPacket {
+            cmd: cmd as u16,
+            direction: MspPacketDirection::ToFlightController,
+            data: data,
+        };
+
+        let cmd = packet.cmd;
+        let (waiter_id, waiter) = INavMsp::wait_for(&self.waiters, cmd).await;
+        self.send_with_priority(packet, MspPriority::Normal).await;
+
+        let timeout_res = future::timeout(Duration::from_millis(30), waiter).await;
+        let reply = match timeout_res {
+            Ok(Ok(reply)) => reply,
+            _ => {
+                INavMsp::forget_waiter(&self.waiters, cmd, waiter_id).await;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "timedout waiting for response"));
+            }
+        };
+
+        // a zero-length reply to a command expecting a populated struct fails
+        // to unpack here, surfacing as a typed error rather than silently
+        // handing back zeroed fields
+        T::unpack_from_slice(&reply.data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("flight controller returned an error ack: {:?}", e)))
+    }
+
+    pub async fn raw_gps(&self) -> io::Result<MspRawGps> {
+        self.request(MspCommandCode::MSP_RAW_GPS, MspEmpty).await
+    }
+
+    pub async fn attitude(&self) -> io::Result<MspAttitude> {
+        self.request(MspCommandCode::MSP_ATTITUDE, MspEmpty).await
+    }
+
+    pub async fn rc(&self) -> io::Result<MspRc> {
+        self.request(MspCommandCode::MSP_RC, MspEmpty).await
+    }
+
+    pub async fn set_raw_rc(&self, rc: MspRc) -> io::Result<()> {
+        self.request::<MspEmpty>(MspCommandCode::MSP_SET_RAW_RC, rc).await.map(|_| ())
+    }
+
+    pub async fn pid(&self) -> io::Result<MspPid> {
+        self.request(MspCommandCode::MSP_PID, MspEmpty).await
+    }
+
+    pub async fn set_pid(&self, pid: MspPid) -> io::Result<()> {
+        self.request::<MspEmpty>(MspCommandCode::MSP_SET_PID, pid).await.map(|_| ())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn high_priority_packet_is_served_before_a_flood_of_bulk_packets() {
+        let (high_send, high_recv) = channel::<MspPacket>(1);
+        let (_normal_send, normal_recv) = channel::<MspPacket>(1);
+        let (bulk_send, bulk_recv) = channel::<MspPacket>(16);
+
+        let flood = task::spawn(async move {
+            for cmd in 0..20u16 {
+                bulk_send.send(MspPacket {
+                    cmd,
+                    direction: MspPacketDirection::ToFlightController,
+                    data: vec![],
+                }).await;
+            }
+        });
+
+        // give the bulk flood a head start so its queue is never empty
+        task::sleep(Duration::from_millis(5)).await;
+
+        high_send.send(MspPacket {
+            cmd: 999,
+            direction: MspPacketDirection::ToFlightController,
+            data: vec![],
+        }).await;
+
+        let mut served_cmds = vec![];
+        while served_cmds.len() < 21 {
+            match INavMsp::next_outgoing(&high_recv, &normal_recv, &bulk_recv).await {
+                Some(packet) => served_cmds.push(packet.cmd),
+                None => break,
+            }
+        }
+
+        flood.await;
+
+        assert_eq!(served_cmds[0], 999, "the High priority packet should jump the Bulk flood");
+    }
 }